@@ -0,0 +1,163 @@
+// ABCdb abcparser_peg.rs – duration.rs
+//
+// Copyright © 2017 Sean Bolton.
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// In the spirit of LilyPond's own bar checks, this computes the total notated duration of each
+// measure (the text between `barline` tokens) and compares it to the active meter, so a tune
+// with malformed bars can be reported instead of silently canonified.
+//
+// Like `transpose_abc_visitor`'s key signature, the running measure total and measure index are
+// threaded from call to call by the caller, since a measure (or a whole tune) can span more than
+// one source line.
+
+extern crate pest;
+
+use pest::prelude::*;
+
+use grammar::{Rdp,Rule};
+use length;
+use tuplet::{self,TupletGroup};
+
+#[derive(Debug, Clone)]
+pub struct MeasureDiscrepancy {
+    pub line: usize,
+    pub measure_index: usize,
+    pub expected: (u32, u32),
+    pub actual: (u32, u32),
+}
+
+// the unit note length ABC implies when no `L:` field has been given, per "3.1.6 M: - meter":
+// 1/16 if the meter is less than 3/4, else 1/8
+pub fn implied_unit_length(meter: (u32, u32)) -> (u32, u32) {
+    if (meter.0 as f64) / (meter.1 as f64) < 0.75 {
+        (1, 16)
+    } else {
+        (1, 8)
+    }
+}
+
+// Running state for an in-progress measure, threaded across lines.
+pub struct MeasureState {
+    pub measure_index: usize,
+    total: (u32, u32),
+    has_multi_rest: bool,
+    carry: Option<(u32, u32)>, // previous stem's note value, held back in case '<'/'>' adjusts it
+    pending_broken_sep: Option<char>,
+}
+
+impl MeasureState {
+    pub fn new() -> MeasureState {
+        MeasureState {
+            measure_index: 0,
+            total: (0, 1),
+            has_multi_rest: false,
+            carry: None,
+            pending_broken_sep: None,
+        }
+    }
+}
+
+// Check each measure of `parser`'s already-parsed tune body line against `meter`, using
+// `unit_length` as the `L:`-derived (or implied) unit note length, `tuplet_groups` (from
+// `tuplet::find_tuplet_groups`, run over the same parser) to shrink notes that fall inside a
+// tuplet by its p:q ratio, and `state` to carry an in-progress measure, and the measure count,
+// across calls. The tune's very first measure (state.measure_index == 0) is not checked at all,
+// short or long, to accommodate a pickup/anacrusis.
+pub fn check_measures(parser: &Rdp<pest::StringInput>, line: usize, meter: (u32, u32),
+                       unit_length: (u32, u32), tuplet_groups: &[TupletGroup], state: &mut MeasureState)
+                       -> Vec<MeasureDiscrepancy> {
+    let q = parser.queue();
+    let qlen = q.len();
+    let input = parser.input().slice(0, parser.input().len());
+    let expected = length::reduce(meter.0, meter.1);
+    let tuplet_ratio = tuplet::ratio_by_member(tuplet_groups);
+
+    let mut discrepancies = Vec::new();
+
+    let mut i = 0;
+    while i < qlen {
+        match q[i].rule {
+            Rule::multi_measure_rest => {
+                state.has_multi_rest = true;
+            }
+            Rule::b_sep => {
+                state.pending_broken_sep = Some(input.as_bytes()[q[i].start] as char);
+            }
+            Rule::stem => {
+                let mut cur = length::stem_length_factor(i, q, qlen, input);
+                if let Some((num, den)) = tuplet_ratio.get(&i) {
+                    cur = length::reduce(cur.0 * num, cur.1 * den);
+                }
+                cur = length::reduce(unit_length.0 * cur.0, unit_length.1 * cur.1);
+
+                // a broken-rhythm separator dots the preceding stem and halves this one (or the
+                // reverse, depending on direction); only a single separator is handled, not the
+                // rarer doubled/tripled "<<"/">>" dotting
+                if let Some(sep) = state.pending_broken_sep.take() {
+                    if let Some(prev) = state.carry {
+                        let (prev_adj, cur_adj) = if sep == '>' {
+                            (mul(prev, (3, 2)), mul(cur, (1, 2)))
+                        } else {
+                            (mul(prev, (1, 2)), mul(cur, (3, 2)))
+                        };
+                        add_to(&mut state.total, prev_adj);
+                        state.carry = Some(cur_adj);
+                        i += 1;
+                        continue;
+                    }
+                }
+                if let Some(prev) = state.carry.take() {
+                    add_to(&mut state.total, prev);
+                }
+                state.carry = Some(cur);
+            }
+            Rule::barline => {
+                if let Some(prev) = state.carry.take() {
+                    add_to(&mut state.total, prev);
+                }
+                let is_pickup = state.measure_index == 0;
+                if !is_pickup && !state.has_multi_rest && state.total != (0, 1) {
+                    let actual = length::reduce(state.total.0, state.total.1);
+                    if actual != expected {
+                        discrepancies.push(MeasureDiscrepancy {
+                            line, measure_index: state.measure_index, expected, actual,
+                        });
+                    }
+                }
+                state.measure_index += 1;
+                state.has_multi_rest = false;
+                state.total = (0, 1);
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    discrepancies
+}
+
+fn mul(a: (u32, u32), b: (u32, u32)) -> (u32, u32) {
+    length::reduce(a.0 * b.0, a.1 * b.1)
+}
+
+fn add_to(total: &mut (u32, u32), note: (u32, u32)) {
+    *total = length::reduce(total.0 * note.1 + note.0 * total.1, total.1 * note.1);
+}
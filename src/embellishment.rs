@@ -0,0 +1,114 @@
+// ABCdb abcparser_peg.rs – embellishment.rs
+//
+// Copyright © 2017 Sean Bolton.
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Pipe-tune ABC encodes named embellishments (doublings, grips, throws, taorluaths, birls) as
+// fixed multi-note `grace_notes` clusters rather than free ornamentation. This recognizes those
+// stereotyped clusters against a configurable table, tagging each `grace_notes` node with its
+// embellishment name, and can go the other way: expand a named embellishment back into its
+// canonical grace-note text for output.
+//
+// The table is deliberately caller-suppliable: Highland, Border, and uilleann pipe-tune
+// transcriptions don't all agree on exact grace-note spellings for a given embellishment name, so
+// `default_highland_table` is only a small illustrative starter set, not an authority.
+
+extern crate pest;
+
+use pest::prelude::*;
+
+use grammar::{Rdp,Rule};
+
+pub struct EmbellishmentTable {
+    // (name, pattern of grace-note pitch texts, e.g. "G", "D", "c")
+    entries: Vec<(String, Vec<String>)>,
+}
+
+impl EmbellishmentTable {
+    pub fn new() -> EmbellishmentTable {
+        EmbellishmentTable { entries: Vec::new() }
+    }
+
+    pub fn add(&mut self, name: &str, pattern: &[&str]) {
+        self.entries.push((name.to_string(), pattern.iter().map(|s| s.to_string()).collect()));
+    }
+}
+
+pub fn default_highland_table() -> EmbellishmentTable {
+    let mut t = EmbellishmentTable::new();
+    t.add("doubling", &["G", "D", "C"]);
+    t.add("grip", &["G", "D", "G"]);
+    t.add("throw", &["G", "D", "C", "D"]);
+    t.add("taorluath", &["G", "D", "E"]);
+    t.add("birl", &["G", "D", "G", "D"]);
+    t
+}
+
+// Walk one already-parsed tune body line, returning the queue index and recognized name of
+// every `grace_notes` node whose pitch sequence exactly matches an entry in `table` (the first
+// matching entry wins).
+pub fn recognize_embellishments(parser: &Rdp<pest::StringInput>, table: &EmbellishmentTable)
+                                 -> Vec<(usize, String)> {
+    let q = parser.queue();
+    let qlen = q.len();
+    let input = parser.input().slice(0, parser.input().len());
+    let mut tagged = Vec::new();
+
+    let mut i = 0;
+    while i < qlen {
+        if q[i].rule == Rule::grace_notes {
+            let pitches = grace_note_pitches(i, q, qlen, input);
+            for (name, pattern) in &table.entries {
+                if pattern == &pitches {
+                    tagged.push((i, name.clone()));
+                    break;
+                }
+            }
+        }
+        i += 1;
+    }
+    tagged
+}
+
+// the literal pitch text (basenote plus octave marks, e.g. "G" or "c'") of each grace_note in a
+// grace_notes node, in order
+fn grace_note_pitches(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str) -> Vec<String> {
+    let end = q[i].end;
+    let mut k = i + 1;
+    let mut pitches = Vec::new();
+    while k < qlen && q[k].start < end {
+        if q[k].rule == Rule::pitch {
+            pitches.push(input[q[k].start..q[k].end].to_string());
+        }
+        k += 1;
+    }
+    pitches
+}
+
+// Expand a named embellishment into its canonical `{ ... }` grace_notes text.
+pub fn expand_embellishment(table: &EmbellishmentTable, name: &str) -> Option<String> {
+    table.entries.iter().find(|(n, _)| n == name).map(|(_, pattern)| {
+        let mut s = String::from("{");
+        s.push_str(&pattern.join(""));
+        s.push('}');
+        s
+    })
+}
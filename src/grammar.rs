@@ -174,22 +174,25 @@ impl_rdp! {
 
     // ==== 4.14 Decorations
 
-    // -FIX- 'I:decoration +' could change '!' to '+'
-    long_gracing = { ( ["!"] ~ ( gracing1 | gracing2 | gracing3 | gracing_nonstandard | gracing4 ) ~
-                       ["!"] ) |
+    // the delimiter used below (the standard '!', or the older '+') is selected by an
+    // 'I:decoration' field in the tune body; see visitors.rs, which normalizes both into
+    // whichever one is currently in force
+    long_gracing = { ( ( ["!"] | ["+"] ) ~
+                       ( gracing1 | gracing2 | gracing3 | gracing_nonstandard | gracing4 ) ~
+                       ( ["!"] | ["+"] ) ) |
                      ( ["!"] ~ gracing_catchall ~ ["!"] ) }
     gracing1 = { ["<("] | ["<)"] | [">("] | [">)"] | ["D.C."] | ["D.S."] | ["accent"] |
-                 ["arpeggio"] | ["breath"] | ["coda"] | ["crescendo("] | ["crescendo)"] |
-                 ["dacapo"] | ["dacoda"] | ["diminuendo("] }
-    gracing2 = { ["diminuendo)"] | ["downbow"] | ["emphasis"] | ["fermata"] | ["ffff"] | ["fff"] |
-                 ["ff"] | ["fine"] | ["invertedfermata"] | ["invertedturnx"] | ["invertedturn"] |
-                 ["longphrase"] | ["lowermordent"] }
-    gracing3 = { ["mediumphrase"] | ["mf"] | ["mordent"] | ["mp"] | ["open"] | ["plus"] | ["pppp"] |
-                 ["ppp"] | ["pp"] | ["pralltriller"] | ["roll"] | ["segno"] | ["sfz"] |
-                 ["shortphrase"] | ["slide"] | ["snap"] }
+                 ["arpeggio"] | ["breath"] | ["caesura"] | ["coda"] | ["comma"] |
+                 ["crescendo("] | ["crescendo)"] | ["dacapo"] | ["dacoda"] | ["diminuendo("] }
+    gracing2 = { ["diminuendo)"] | ["downbow"] | ["downmordent"] | ["emphasis"] | ["fermata"] |
+                 ["ffff"] | ["fff"] | ["ff"] | ["fine"] | ["invertedfermata"] | ["invertedturnx"] |
+                 ["invertedturn"] | ["lineprall"] | ["longphrase"] | ["lowermordent"] }
+    gracing3 = { ["mediumphrase"] | ["mf"] | ["mordent"] | ["mp"] | ["open"] | ["plus"] |
+                 ["pppp"] | ["ppp"] | ["pp"] | ["pralldown"] | ["prallup"] | ["pralltriller"] |
+                 ["roll"] | ["segno"] | ["sfz"] | ["shortphrase"] | ["slide"] | ["snap"] }
     gracing4 = { ["tenuto"] | ["thumb"] | ["trill("] | ["trill)"] | ["trill"] | ["turnx"] |
-                 ["turn"] | ["upbow"] | ["uppermordent"] | ["wedge"] | ["+"] | ['0'..'5'] | ["<"] |
-                 [">"] | ["f"] | ["p"] }
+                 ["turn"] | ["upbow"] | ["upmordent"] | ["uppermordent"] | ["wedge"] | ["+"] |
+                 ['0'..'5'] | ["<"] | [">"] | ["f"] | ["p"] }
     gracing_nonstandard = { ["cresc"] | ["decresc"] | ["dimin"] | ["fp"] |
                             ( ["repeatbar"] ~ DIGITS ) }  // non-standard, from Norbeck
     gracing_catchall = { ['"'..'~']+ }  // catch-all for non-standard ABC
@@ -0,0 +1,89 @@
+// ABCdb abcparser_peg.rs – length.rs
+//
+// Copyright © 2017 Sean Bolton.
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Fraction arithmetic over note lengths (`4.3 Note lengths`), shared by anything that needs to
+// know how long a note actually is: the LilyPond backend (for duration + dots) and the measure
+// duration checker (for summing a bar's total).
+
+extern crate pest;
+
+use pest::prelude::*;
+
+use grammar::Rule;
+
+pub(crate) fn reduce(n: u32, d: u32) -> (u32, u32) {
+    fn gcd(a: u32, b: u32) -> u32 { if b == 0 { a } else { gcd(b, a % b) } }
+    let g = gcd(n, d).max(1);
+    (n / g, d / g)
+}
+
+// parse a `note_length_strict` match (used by the `L:` and `Q:` fields), which is always either
+// a bare "1" or an explicit "n/d"
+pub(crate) fn note_length_strict_fraction(text: &str) -> (u32, u32) {
+    if text == "1" {
+        (1, 1)
+    } else if let Some(slash) = text.find('/') {
+        let n: u32 = text[..slash].parse().unwrap_or(1);
+        let d: u32 = text[slash + 1..].parse().unwrap_or(1);
+        (n, d)
+    } else {
+        (1, 1)
+    }
+}
+
+// the factor a `note_length` applies to the default unit note length
+pub(crate) fn note_length_factor(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str) -> (u32, u32) {
+    let end = q[i].end;
+    let mut k = i + 1;
+    while k < qlen && q[k].start < end {
+        let text = &input[q[k].start..q[k].end];
+        match q[k].rule {
+            Rule::note_length_bigger => return (text.parse().unwrap_or(1), 1),
+            Rule::note_length_smaller => return (1, text[1..].parse().unwrap_or(1)),
+            Rule::note_length_full => {
+                let slash = text.find('/').unwrap();
+                return (text[..slash].parse().unwrap_or(1), text[slash + 1..].parse().unwrap_or(1));
+            }
+            Rule::note_length_slashes => return (1, 1 << text.len()),
+            _ => {}
+        }
+        k += 1;
+    }
+    (1, 1)
+}
+
+// a `stem`'s own note_length factor, ignoring broken rhythm and tuplets (the bracketed chord form
+// takes its duration from its first note, per ABC convention); shared by anything that needs a
+// stem's notated length without caring about its surrounding context
+pub(crate) fn stem_length_factor(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str) -> (u32, u32) {
+    // search only the stem's first child: the note (the first one, for a chord) or the rest
+    let end = if i + 1 < qlen && q[i + 1].start < q[i].end { q[i + 1].end } else { q[i].end };
+    let mut k = i + 1;
+    while k < qlen && q[k].start < end {
+        if q[k].rule == Rule::note_length {
+            return note_length_factor(k, q, qlen, input);
+        }
+        k += 1;
+    }
+    (1, 1)
+}
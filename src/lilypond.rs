@@ -0,0 +1,398 @@
+// ABCdb abcparser_peg.rs – lilypond.rs
+//
+// Copyright © 2017 Sean Bolton.
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// A second backend visitor, this one emitting LilyPond (mudela) source from the same parse
+// queue the canonifier in visitors.rs walks -- the reverse of LilyPond's own convert-mudela
+// direction. As with the canonifier, unchanged runs of input are accumulated as slices rather
+// than allocated, via the RString/RSlice machinery defined in visitors.rs.
+
+extern crate pest;
+
+use std::collections::HashSet;
+
+use pest::prelude::*;
+
+use grammar::{Rdp,Rule};
+use length;
+use pitch;
+use tuplet;
+use visitors::RString;
+
+// running state for converting one line, seeded from the caller's unit_length on entry and
+// updated in place by any `L:` field the line itself contains
+struct LyState {
+    len_num: u32,
+    len_den: u32,
+    // queue index of the last `stem` in each tuplet group found by `tuplet::find_tuplet_groups`,
+    // i.e. where the `\times p/q { ... }` opened by `Rule::tuplet` needs its matching `}`
+    tuplet_closes: HashSet<usize>,
+}
+
+// Convert one already-parsed tune body line to LilyPond source. `unit_length` is the `L:`-derived
+// (or, per `duration::implied_unit_length`, meter-implied) default note length in effect as of
+// this line, the same way `duration::check_measures` and `voice::separate_voices` take it --
+// `music_code_line` parses one line at a time, so the caller is the one tracking it across lines
+// and header fields.
+pub fn to_lilypond(parser: &Rdp<pest::StringInput>, unit_length: (u32, u32)) -> String {
+    let q = parser.queue();
+    let qlen = q.len();
+    let ilen = parser.input().len();
+    let input = parser.input().slice(0, ilen);
+    let (tuplet_groups, _warnings) = tuplet::find_tuplet_groups(parser);
+    let tuplet_closes = tuplet_groups.iter()
+        .filter_map(|g| g.members.last().cloned())
+        .collect();
+    let mut state = LyState { len_num: unit_length.0, len_den: unit_length.1, tuplet_closes };
+    let mut result = String::new();
+    let mut i = 0;
+    while i < qlen {
+        let (rstring, new_i) = _convert(i, q, qlen, input, &mut state);
+        i = new_i;
+        result.push_str(&rstring.to_string(input));
+        result.push(' ');
+    }
+    result
+}
+
+// default behavior: recurse into children, converting each, and pass any of this node's own
+// text that falls between children through unchanged
+fn _gather_ly(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str, state: &mut LyState) -> (RString, usize) {
+    let mut child_i = i + 1;
+    if child_i < qlen && q[child_i].start < q[i].end {
+        let mut text_offset = q[i].start;
+        let mut rstr;
+        if text_offset < q[child_i].start {
+            rstr = RString::from_slice(text_offset, q[child_i].start);
+            let (rstr2, new_i) = _convert(child_i, q, qlen, input, state);
+            rstr = rstr.add(rstr2, input);
+            text_offset = q[child_i].end;
+            child_i = new_i;
+        } else {
+            let (rstr2, new_i) = _convert(child_i, q, qlen, input, state);
+            rstr = rstr2;
+            text_offset = q[child_i].end;
+            child_i = new_i;
+        }
+        while child_i < qlen && q[child_i].start < q[i].end {
+            if text_offset < q[child_i].start {
+                rstr = rstr.add(RString::from_slice(text_offset, q[child_i].start), input);
+            }
+            let (rstr2, new_i) = _convert(child_i, q, qlen, input, state);
+            rstr = rstr.add(rstr2, input);
+            text_offset = q[child_i].end;
+            child_i = new_i;
+        }
+        if text_offset < q[i].end {
+            rstr = rstr.add(RString::from_slice(text_offset, q[i].end), input);
+        }
+        (rstr, child_i)
+    } else {
+        (RString::from_slice(q[i].start, q[i].end), i + 1)
+    }
+}
+
+fn _children_end(i: usize, q: &Vec<Token<Rule>>, qlen: usize) -> usize {
+    let end = q[i].end;
+    let mut k = i + 1;
+    while k < qlen && q[k].start < end {
+        k += 1;
+    }
+    k
+}
+
+fn _convert(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str, state: &mut LyState) -> (RString, usize) {
+    match q[i].rule {
+        Rule::ifield_length => {
+            let new_i = _children_end(i, q, qlen);
+            // find the note_length_strict child and adopt it as the new default length
+            let mut k = i + 1;
+            while k < new_i {
+                if q[k].rule == Rule::note_length_strict {
+                    let (n, d) = length::note_length_strict_fraction(&input[q[k].start..q[k].end]);
+                    state.len_num = n;
+                    state.len_den = d;
+                }
+                k += 1;
+            }
+            (RString::Str(String::new()), new_i)
+        }
+        Rule::ifield_meter => {
+            let new_i = _children_end(i, q, qlen);
+            let mut k = i + 1;
+            let mut out = None;
+            while k < new_i {
+                if q[k].rule == Rule::meter {
+                    out = Some(meter_to_lilypond(&input[q[k].start..q[k].end]));
+                }
+                k += 1;
+            }
+            (RString::Str(out.unwrap_or_default()), new_i)
+        }
+        Rule::ifield_key => {
+            let new_i = _children_end(i, q, qlen);
+            let mut k = i + 1;
+            let mut out = None;
+            while k < new_i {
+                if q[k].rule == Rule::key {
+                    out = Some(key_to_lilypond(k, q, qlen, input));
+                }
+                k += 1;
+            }
+            (RString::Str(out.unwrap_or_default()), new_i)
+        }
+        Rule::stem => {
+            let end = q[i].end;
+            let closes_tuplet = state.tuplet_closes.contains(&i);
+            let (mut rstr, new_i) = if input[q[i].start..q[i].end].starts_with('[') {
+                // bracketed chord: "[" note note+ "]" tie?
+                let mut s = String::from("<");
+                let mut k = i + 1;
+                let mut first = true;
+                let mut tie = false;
+                while k < qlen && q[k].start < end {
+                    if q[k].rule == Rule::note {
+                        if !first { s.push(' '); }
+                        first = false;
+                        let (rstr, new_k) = note_to_lilypond(k, q, qlen, input, state);
+                        s.push_str(&rstr.to_string(input));
+                        k = new_k;
+                    } else if q[k].rule == Rule::tie {
+                        tie = true;
+                        k += 1;
+                    } else {
+                        k += 1;
+                    }
+                }
+                s.push('>');
+                if tie {
+                    s.push('~');
+                }
+                (RString::Str(s), _children_end(i, q, qlen))
+            } else {
+                _gather_ly(i, q, qlen, input, state)
+            };
+            if closes_tuplet {
+                rstr = rstr.add(RString::Str("}".to_string()), input);
+            }
+            (rstr, new_i)
+        }
+        Rule::note => note_to_lilypond(i, q, qlen, input, state),
+        Rule::rest => {
+            let end = q[i].end;
+            let kind = input.as_bytes()[q[i].start] as char;
+            let lyname = if kind == 'y' { 's' } else { 'r' };
+            let mut k = i + 1;
+            let mut factor = (1, 1);
+            while k < qlen && q[k].start < end {
+                if q[k].rule == Rule::note_length {
+                    factor = length::note_length_factor(k, q, qlen, input);
+                }
+                k += 1;
+            }
+            let (num, den) = length::reduce(state.len_num * factor.0, state.len_den * factor.1);
+            let (dur, dots) = fraction_to_duration(num, den);
+            let mut s = lyname.to_string();
+            s.push_str(&dur.to_string());
+            for _ in 0..dots { s.push('.'); }
+            (RString::Str(s), _children_end(i, q, qlen))
+        }
+        Rule::tie => (RString::Str("~".to_string()), i + 1),
+        Rule::slur_begin => (RString::Str("(".to_string()), i + 1),
+        Rule::slur_end => (RString::Str(")".to_string()), i + 1),
+        Rule::tuplet => {
+            // the matching close brace is emitted by the Rule::stem arm, once it reaches the
+            // last member of this group as located by tuplet::find_tuplet_groups (state.tuplet_closes)
+            let end = q[i].end;
+            let mut k = i + 1;
+            let mut p = String::new();
+            let mut q_ratio = String::new();
+            let mut r = String::new();
+            let mut seen_digits = 0;
+            while k < qlen && q[k].start < end {
+                if q[k].rule == Rule::DIGITS {
+                    let text = &input[q[k].start..q[k].end];
+                    match seen_digits {
+                        0 => p = text.to_string(),
+                        1 => q_ratio = text.to_string(),
+                        _ => r = text.to_string(),
+                    }
+                    seen_digits += 1;
+                }
+                k += 1;
+            }
+            if q_ratio.is_empty() {
+                // ABC's implied q when it is not given explicitly: 3 when p is 2, 4 or 8,
+                // otherwise 2 (the same simple-meter table as tuplet's default_q)
+                q_ratio = match p.as_str() { "2" => "3", "3" => "2", "4" => "3", "8" => "3", _ => "2" }.to_string();
+            }
+            let _ = r; // the 'r' (number of notes the ratio spans) isn't needed for \times itself
+            (RString::Str(format!("\\times {}/{} {{", q_ratio, p)), _children_end(i, q, qlen))
+        }
+        Rule::long_gracing | Rule::gracing1 | Rule::gracing2 | Rule::gracing3 | Rule::gracing4 |
+        Rule::gracing_nonstandard | Rule::gracing_catchall => {
+            let text = &input[q[i].start..q[i].end];
+            // a long_gracing's text carries its '!'/'+' delimiters; the name lies between them
+            let name = if q[i].rule == Rule::long_gracing { &text[1..text.len() - 1] } else { text };
+            (RString::Str(gracing_to_lilypond(name)), _children_end(i, q, qlen))
+        }
+        _ => _gather_ly(i, q, qlen, input, state),
+    }
+}
+
+fn note_to_lilypond(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str, state: &LyState)
+                     -> (RString, usize) {
+    let end = q[i].end;
+    let mut k = i + 1;
+    let mut accidental: Option<&str> = None;
+    let mut basenote = 'C';
+    let mut octave_marks = 0i32;
+    let mut factor = (1, 1);
+    let mut tie = false;
+    while k < qlen && q[k].start < end {
+        match q[k].rule {
+            Rule::accidental => accidental = Some(&input[q[k].start..q[k].end]),
+            Rule::basenote => basenote = input[q[k].start..q[k].end].chars().next().unwrap(),
+            Rule::octave => octave_marks = pitch::octave_marks(&input[q[k].start..q[k].end]),
+            Rule::note_length => factor = length::note_length_factor(k, q, qlen, input),
+            Rule::tie => tie = true,
+            _ => {}
+        }
+        k += 1;
+    }
+    let mut s = String::new();
+    s.push(basenote.to_ascii_lowercase());
+    if let Some(acc) = accidental {
+        s.push_str(match acc {
+            "^^" => "isis", "^" => "is", "__" => "eses", "_" => "es", _ => "",
+        });
+    }
+    let total_octave = pitch::base_octave(basenote) + octave_marks;
+    let marks = total_octave - 3;
+    if marks > 0 {
+        for _ in 0..marks { s.push('\''); }
+    } else if marks < 0 {
+        for _ in 0..(-marks) { s.push(','); }
+    }
+    let (num, den) = length::reduce(state.len_num * factor.0, state.len_den * factor.1);
+    let (dur, dots) = fraction_to_duration(num, den);
+    s.push_str(&dur.to_string());
+    for _ in 0..dots { s.push('.'); }
+    if tie {
+        s.push('~');
+    }
+    (RString::Str(s), k)
+}
+
+// convert a (num, den) fraction of a whole note into a LilyPond duration number and dot count;
+// falls back to an approximate quarter note for ratios (e.g. from unreduced tuplets) that are
+// not an exact power-of-two-with-dots
+fn fraction_to_duration(num: u32, den: u32) -> (u32, usize) {
+    if den == 0 || num == 0 || den & (den - 1) != 0 {
+        return (4, 0); // -FIX- non-power-of-two denominator; needs tuplet-aware duration math
+    }
+    let e = den.trailing_zeros();
+    if (num + 1) & num != 0 {
+        return (4, 0); // -FIX- not a plain-or-dotted length
+    }
+    let dots = (num + 1).trailing_zeros() - 1;
+    if dots > e {
+        return (4, 0);
+    }
+    let p = e - dots;
+    (1 << p, dots as usize)
+}
+
+fn meter_to_lilypond(text: &str) -> String {
+    if text == "C" || text == "c" || text == "C|" {
+        "\\time 4/4 ".to_string()
+    } else if let Some(slash) = text.find('/') {
+        format!("\\time {}/{} ", &text[..slash], &text[slash + 1..])
+    } else {
+        String::new()
+    }
+}
+
+fn key_to_lilypond(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str) -> String {
+    let end = q[i].end;
+    let mut k = i + 1;
+    while k < qlen && q[k].start < end {
+        if q[k].rule == Rule::key_def {
+            let def_end = q[k].end;
+            let basenote_i = k + 1;
+            let basenote = input[q[basenote_i].start..q[basenote_i].end].chars().next().unwrap();
+            let after = q[basenote_i].end;
+            let mut m = basenote_i + 1;
+            let mode_start = if m < qlen && q[m].start < def_end { q[m].start } else { def_end };
+            let accidental = match &input[after..mode_start] {
+                "#" | "♯" => "is", "b" | "♭" => "es", _ => "",
+            };
+            let mut mode = "major";
+            while m < qlen && q[m].start < def_end {
+                mode = match q[m].rule {
+                    Rule::major => "major", Rule::lydian => "lydian", Rule::ionian => "ionian",
+                    Rule::mixolydian => "mixolydian", Rule::dorian => "dorian",
+                    Rule::aeolian => "aeolian", Rule::phrygian => "phrygian",
+                    Rule::locrian => "locrian", Rule::minor => "minor",
+                    _ => mode,
+                };
+                m += 1;
+            }
+            return format!("\\key {}{} \\{} ", basenote.to_ascii_lowercase(), accidental, mode);
+        }
+        k += 1;
+    }
+    String::new()
+}
+
+fn gracing_to_lilypond(name: &str) -> String {
+    match name {
+        "." => "-.".to_string(),
+        "fermata" | "invertedfermata" => "\\fermata".to_string(),
+        "trill" => "\\trill".to_string(),
+        "turn" => "\\turn".to_string(),
+        "turnx" | "invertedturn" | "invertedturnx" => "\\reverseturn".to_string(),
+        "mordent" | "lowermordent" | "downmordent" => "\\mordent".to_string(),
+        "uppermordent" | "upmordent" | "pralltriller" => "\\prall".to_string(),
+        "pralldown" => "\\pralldown".to_string(),
+        "prallup" => "\\prallup".to_string(),
+        "lineprall" => "\\lineprall".to_string(),
+        "caesura" => "\\caesura".to_string(),
+        "comma" => "\\breathe".to_string(),
+        "upbow" => "\\upbow".to_string(),
+        "downbow" => "\\downbow".to_string(),
+        "accent" | "emphasis" => "->".to_string(),
+        "tenuto" => "-_".to_string(),
+        "staccato" | "plus" => "-.".to_string(),
+        "snap" => "\\snappizzicato".to_string(),
+        "open" => "\\open".to_string(),
+        "thumb" => "\\thumb".to_string(),
+        "segno" => "\\segno".to_string(),
+        "coda" => "\\coda".to_string(),
+        "breath" => "\\breathe".to_string(),
+        "D.C." => "\\mark \\markup { \"D.C.\" }".to_string(),
+        "D.S." => "\\mark \\markup { \"D.S.\" }".to_string(),
+        "pp" | "ppp" | "pppp" | "p" | "mp" | "mf" | "f" | "ff" | "fff" | "ffff" | "sfz" | "fp" =>
+            format!("\\{}", name),
+        _ => format!("^\\markup {{ \"{}\" }}", name),
+    }
+}
@@ -0,0 +1,153 @@
+// ABCdb abcparser_peg.rs – pitch.rs
+//
+// Copyright © 2017 Sean Bolton.
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Shared pitch and key-signature arithmetic used by the transposition visitor and the LilyPond
+// backend. This is the diatonic-step model that `4.1 Pitch` and `3.1.14 K: - key` describe: a
+// note is a (diatonic step 0-6 from C, chromatic alteration in semitones, octave) triple, and a
+// key signature is just a default alteration for each of the seven steps.
+
+// natural semitone of each diatonic step, C through B
+pub(crate) const NATURAL_SEMITONE: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+// basenote letters in diatonic-step order, C through B
+const STEP_LETTERS: [char; 7] = ['C', 'D', 'E', 'F', 'G', 'A', 'B'];
+
+// fifths value (position on the circle of fifths, relative to C) of each natural basenote
+const LETTER_FIFTHS: [i32; 7] = [0, 2, 4, -1, 1, 3, 5]; // C D E F G A B
+
+// order in which sharps/flats are added to a key signature as its fifths count rises
+const SHARP_ORDER: [usize; 7] = [3, 0, 4, 1, 5, 2, 6]; // F C G D A E B
+const FLAT_ORDER: [usize; 7] = [6, 2, 5, 1, 4, 0, 3];  // B E A D G C F
+
+pub(crate) fn step_of(basenote: char) -> usize {
+    match basenote.to_ascii_uppercase() {
+        'C' => 0, 'D' => 1, 'E' => 2, 'F' => 3, 'G' => 4, 'A' => 5, 'B' => 6,
+        _ => unreachable!("basenote is restricted to A-G/a-g by the grammar"),
+    }
+}
+
+pub(crate) fn letter_of(step: usize) -> char {
+    STEP_LETTERS[step % 7]
+}
+
+// octave number of a basenote on its own (uppercase is one octave below the matching lowercase)
+pub(crate) fn base_octave(basenote: char) -> i32 {
+    if basenote.is_ascii_uppercase() { 4 } else { 5 }
+}
+
+pub(crate) fn octave_marks(octave_text: &str) -> i32 {
+    if octave_text.starts_with('\'') {
+        octave_text.chars().count() as i32
+    } else if octave_text.starts_with(',') {
+        -(octave_text.chars().count() as i32)
+    } else {
+        0
+    }
+}
+
+pub(crate) fn octave_text(marks: i32) -> String {
+    if marks > 0 {
+        "'".repeat(marks as usize)
+    } else if marks < 0 {
+        ",".repeat((-marks) as usize)
+    } else {
+        String::new()
+    }
+}
+
+// semitone alteration represented by one of the ABC accidental tokens
+pub(crate) fn accidental_alteration(accidental: &str) -> i32 {
+    match accidental {
+        "^^" => 2, "^" => 1, "=" => 0, "_" => -1, "__" => -2,
+        _ => unreachable!("accidental is restricted by the grammar"),
+    }
+}
+
+// render a semitone alteration, counted from the step's natural pitch, as an ABC accidental
+// token; 0 gives None, so a caller that needs an explicit natural supplies "=" itself
+//
+// `alteration` should always fall in -2..=2: ABC has no accidental beyond double-sharp/flat, and
+// `transpose_pitch`/`transpose_key` only ever produce a wider value when handed a
+// `(diatonic_steps, semitones)` pair that isn't a real interval (see `transpose_abc_visitor`'s
+// doc comment). The catch-all below is a last-resort fallback for that caller error, not a
+// supported case -- it emits a natural, which is the wrong pitch, rather than panicking.
+pub(crate) fn alteration_to_accidental(alteration: i32) -> Option<&'static str> {
+    match alteration {
+        2 => Some("^^"), 1 => Some("^"), 0 => None, -1 => Some("_"), -2 => Some("__"),
+        _ => Some("="),
+    }
+}
+
+// A key signature is the default alteration (in semitones) of each diatonic step, C through B,
+// as implied by the K: field's tonic, accidental, mode, and any appended global_accidental
+// clauses.
+pub(crate) type KeySignature = [i32; 7];
+
+// mode_offset is the tonic's position, in fifths, relative to the major scale sharing its tonic
+// letter -- e.g. minor is 3 fifths flatter than major ('C' and 'Am' share a signature, while 'A'
+// major is three fifths sharper than 'C').
+fn mode_offset(mode: &str) -> i32 {
+    match mode {
+        "lyd" | "lydian" => 1,
+        "ion" | "ionian" | "maj" | "major" => 0,
+        "mix" | "mixolydian" => -1,
+        "dor" | "dorian" => -2,
+        "aeo" | "aeolian" | "m" | "min" | "minor" => -3,
+        "phr" | "phrygian" => -4,
+        "loc" | "locrian" => -5,
+        _ => 0,
+    }
+}
+
+// Build a key signature from a tonic basenote, an optional tonic accidental ("#"/"b"/"♯"/"♭"),
+// and a mode name (as matched by the `mode` rule, lower-cased and truncated the way ABC allows,
+// e.g. "maj", "dor", "m"). Defaults to major when `mode` is empty.
+pub(crate) fn key_signature(tonic: char, tonic_accidental: Option<&str>, mode: &str) -> KeySignature {
+    let mut fifths = LETTER_FIFTHS[step_of(tonic)];
+    fifths += match tonic_accidental {
+        Some("#") | Some("♯") => 7,
+        Some("b") | Some("♭") => -7,
+        _ => 0,
+    };
+    fifths += mode_offset(mode);
+
+    let mut sig: KeySignature = [0; 7];
+    if fifths > 0 {
+        for &step in SHARP_ORDER.iter().take(fifths.min(7) as usize) {
+            sig[step] = 1;
+        }
+    } else if fifths < 0 {
+        for &step in FLAT_ORDER.iter().take((-fifths).min(7) as usize) {
+            sig[step] = -1;
+        }
+    }
+    sig
+}
+
+// Apply the `global_accidental` clauses appended to a `key_def` (e.g. the "^f" in "K:Dmaj ^f"),
+// each an (accidental, basenote) pair overriding that step's signature default.
+pub(crate) fn apply_global_accidentals(sig: &mut KeySignature, accidentals: &[(String, char)]) {
+    for (accidental, basenote) in accidentals {
+        sig[step_of(*basenote)] = accidental_alteration(accidental);
+    }
+}
@@ -0,0 +1,151 @@
+// ABCdb abcparser_peg.rs – tuplet.rs
+//
+// Copyright © 2017 Sean Bolton.
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// As the comment on the grammar's `tuplet` rule admits, a PEG cannot bind "the next N elements"
+// to a `(N` marker -- the parser has no way to know, while matching `tuplet`, how far its group
+// extends. This is a post-parse pass over the token queue that does that binding: for each
+// `tuplet`, it scans forward counting rhythmic units (one per `stem`, i.e. per note, chord, or
+// rest) until it has accounted for the group's span, recording which `stem`s belong to the group.
+
+extern crate pest;
+
+use std::collections::HashMap;
+
+use pest::prelude::*;
+
+use grammar::{Rdp,Rule};
+
+#[derive(Debug, Clone)]
+pub struct TupletGroup {
+    pub tuplet_index: usize,    // queue index of the `tuplet` token itself
+    pub members: Vec<usize>,    // queue indices of the `stem`s making up the group, in order
+    pub p: u32,                 // p notes...
+    pub q: u32,                 // ...in the time of q
+    pub r: u32,                 // ...spanning r source notes (defaults to p)
+}
+
+#[derive(Debug, Clone)]
+pub struct TupletWarning {
+    pub tuplet_index: usize,
+    pub message: String,
+}
+
+// the implied 'q' when a tuplet gives only 'p', e.g. "(3" -- this is ABC's simple-meter default;
+// the full spec table also depends on whether the active meter is compound, which this pass
+// does not track, so odd group sizes in compound time will get the simple-time default
+fn default_q(p: u32) -> u32 {
+    match p {
+        2 => 3,
+        3 => 2,
+        4 => 3,
+        5 => 2,
+        6 => 2,
+        7 => 2,
+        8 => 3,
+        9 => 2,
+        _ => 2,
+    }
+}
+
+pub fn find_tuplet_groups(parser: &Rdp<pest::StringInput>) -> (Vec<TupletGroup>, Vec<TupletWarning>) {
+    let q = parser.queue();
+    let qlen = q.len();
+    let input = parser.input().slice(0, parser.input().len());
+    let mut groups = Vec::new();
+    let mut warnings = Vec::new();
+
+    let mut i = 0;
+    while i < qlen {
+        if q[i].rule == Rule::tuplet {
+            let (p, qr, r, children_end) = parse_tuplet_counts(i, q, qlen, input);
+            let mut members = Vec::new();
+            let mut units = 0;
+            let mut idx = children_end;
+            let mut ran_off_end = false;
+            let mut hit_barline = false;
+            while units < r {
+                if idx >= qlen {
+                    ran_off_end = true;
+                    break;
+                }
+                match q[idx].rule {
+                    Rule::barline => {
+                        hit_barline = true;
+                        break;
+                    }
+                    Rule::stem => {
+                        members.push(idx);
+                        units += 1;
+                        idx += 1;
+                    }
+                    _ => idx += 1,
+                }
+            }
+            if hit_barline || ran_off_end {
+                warnings.push(TupletWarning {
+                    tuplet_index: i,
+                    message: format!(
+                        "tuplet ({}:{}:{} at queue index {}) {} before its group of {} note(s) was complete",
+                        p, qr, r, i,
+                        if hit_barline { "ran into a barline" } else { "ran off the end of the line" },
+                        r),
+                });
+            }
+            groups.push(TupletGroup { tuplet_index: i, members, p, q: qr, r });
+            i = children_end;
+        } else {
+            i += 1;
+        }
+    }
+    (groups, warnings)
+}
+
+// maps each tuplet member's queue index (a `stem`) to the (q, p) ratio shrinking it -- the
+// inverse of TupletGroup's own (p, q), since a note's length is multiplied by q/p inside the
+// group; shared by anything that needs to fold tuplet shrinkage into a stem's notated length
+pub(crate) fn ratio_by_member(groups: &[TupletGroup]) -> HashMap<usize, (u32, u32)> {
+    let mut ratio = HashMap::new();
+    for g in groups {
+        for &m in &g.members {
+            ratio.insert(m, (g.q, g.p));
+        }
+    }
+    ratio
+}
+
+// parse a tuplet's own "(p" or "(p:q" or "(p:q:r" text, returning (p, q, r, index-after-tuplet)
+fn parse_tuplet_counts(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str) -> (u32, u32, u32, usize) {
+    let end = q[i].end;
+    let mut k = i + 1;
+    let mut digits = Vec::new();
+    while k < qlen && q[k].start < end {
+        if q[k].rule == Rule::DIGITS {
+            digits.push(input[q[k].start..q[k].end].parse::<u32>().unwrap_or(1));
+        }
+        k += 1;
+    }
+    let p = *digits.first().unwrap_or(&2);
+    let qr = *digits.get(1).unwrap_or(&default_q(p));
+    let r = *digits.get(2).unwrap_or(&p);
+    (p, qr, r, k)
+}
@@ -28,6 +28,7 @@ use std::iter::FromIterator;
 use pest::prelude::*;
 
 use grammar::{Rdp,Rule};
+use pitch;
 
 // ======== RSlice and RString ========
 
@@ -35,37 +36,39 @@ use grammar::{Rdp,Rule};
 // parse tree. Since this most often involves simply concatenating adjacent slices of the input,
 // the RString 'Slice' variant is used to accumulate the pieces without allocating a String. Only
 // when a parse rule result needs to be changed will a String need to be allocated.
+//
+// pub(crate) so other visitors (e.g. the LilyPond backend) can reuse the same accumulation
+// machinery instead of re-deriving it.
 
 #[derive(Debug)]
-struct RSlice {
+pub(crate) struct RSlice {
     start: usize,
     end: usize
 }
 
 #[derive(Debug)]
-enum RString {
+pub(crate) enum RString {
     Slice(RSlice),
     Str(String)
 }
 
 impl RString {
-    fn from_slice(start: usize, end: usize) -> RString {
+    pub(crate) fn from_slice(start: usize, end: usize) -> RString {
         RString::Slice(RSlice { start, end })
     }
 
-    #[allow(unused)]
     fn from_str(s: &str) -> RString {
         RString::Str(s.to_string())
     }
 
-    fn to_string(self, input: &str) -> String {
+    pub(crate) fn to_string(self, input: &str) -> String {
         match self {
             RString::Slice(slice) => { String::from_iter(input[slice.start..slice.end].chars()) }
             RString::Str(s) => { s }
         }
     }
 
-    fn add(self, other: RString, input: &str) -> RString {
+    pub(crate) fn add(self, other: RString, input: &str) -> RString {
         match (self, other) {
             (RString::Slice(mut lslice), RString::Slice(rslice)) => {
                 if lslice.end == rslice.start {
@@ -99,7 +102,8 @@ impl RString {
 // A parse tree visitor returns a String built from the parsed text, with any necessary changes
 // applied.
 
-fn _gather_children(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str) -> (RString, usize) {
+fn _gather_children(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str, decoration_delim: &mut char)
+                     -> (RString, usize) {
     let mut child_i = i + 1;
     if child_i < qlen && q[child_i].start < q[i].end {
         // there are children to recurse into
@@ -109,13 +113,13 @@ fn _gather_children(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str) ->
             // gather plain text before first child
             rstr = RString::from_slice(text_offset, q[child_i].start);
             // gather first child
-            let (rstr2, new_i) = _recurse_children(child_i, q, qlen, input);
+            let (rstr2, new_i) = _recurse_children(child_i, q, qlen, input, decoration_delim);
             rstr = rstr.add(rstr2, input);
             text_offset = q[child_i].end;
             child_i = new_i;
         } else {
             // gather first child
-            let (rstr2, new_i) = _recurse_children(child_i, q, qlen, input);
+            let (rstr2, new_i) = _recurse_children(child_i, q, qlen, input, decoration_delim);
             rstr = rstr2;
             text_offset = q[child_i].end;
             child_i = new_i;
@@ -126,7 +130,7 @@ fn _gather_children(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str) ->
                 rstr = rstr.add(RString::from_slice(text_offset, q[child_i].start), input);
             }
             // gather child
-            let (rstr2, new_i) = _recurse_children(child_i, q, qlen, input);
+            let (rstr2, new_i) = _recurse_children(child_i, q, qlen, input, decoration_delim);
             rstr = rstr.add(rstr2, input);
             text_offset = q[child_i].end;
             child_i = new_i;
@@ -142,7 +146,7 @@ fn _gather_children(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str) ->
     }
 }
 
-fn visit_parse_tree(parser: &Rdp<pest::StringInput>) -> String {
+fn visit_parse_tree(parser: &Rdp<pest::StringInput>, decoration_delim: &mut char) -> String {
     let q = parser.queue();
     let qlen = q.len();
     let ilen = parser.input().len();
@@ -150,18 +154,19 @@ fn visit_parse_tree(parser: &Rdp<pest::StringInput>) -> String {
     let mut result = String::new();
     let mut i = 0;
     while i < qlen {
-        let (rstring, new_i) = _recurse_children(i, q, qlen, input);
+        let (rstring, new_i) = _recurse_children(i, q, qlen, input, decoration_delim);
         i = new_i;
         result.push_str(&rstring.to_string(input));
     }
     result
 }
 
-fn _recurse_children(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str) -> (RString, usize) {
+fn _recurse_children(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str, decoration_delim: &mut char)
+                      -> (RString, usize) {
     match q[i].rule {
         // !FIX! canonicize all the "non-standard, from Norbeck" things
         Rule::abc_eol => {
-            let (rstr, new_i) = _gather_children(i, q, qlen, input);
+            let (rstr, new_i) = _gather_children(i, q, qlen, input, decoration_delim);
             // trim trailing whitespace
             let s = rstr.to_string(input).trim().to_string();
             (RString::Str(s), new_i)
@@ -178,15 +183,299 @@ fn _recurse_children(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str) -
                 (RString::Str(' '.to_string()), i + 1)
             }
         }
+        Rule::ifield_text => {
+            // watch for 'I:decoration +' / 'I:decoration !', which selects which delimiter the
+            // rest of the tune body uses for long_gracing (see the comment above the grammar's
+            // long_gracing rule)
+            let (rstr, new_i) = _gather_children(i, q, qlen, input, decoration_delim);
+            let text = rstr.to_string(input);
+            if let Some(body) = text.strip_prefix("[I:") {
+                let body = body.trim_end_matches(']').trim();
+                let mut parts = body.splitn(2, char::is_whitespace);
+                if parts.next() == Some("decoration") {
+                    if let Some(c) = parts.next().and_then(|s| s.trim().chars().next()) {
+                        if c == '+' || c == '!' {
+                            *decoration_delim = c;
+                        }
+                    }
+                }
+            }
+            (RString::Str(text), new_i)
+        }
+        Rule::long_gracing => {
+            // canonicalize whichever delimiter ('!name!' or the older '+name+') the source used
+            // into the one currently selected by 'I:decoration'
+            let (rstr, new_i) = _gather_children(i, q, qlen, input, decoration_delim);
+            let text = rstr.to_string(input);
+            // exactly one delimiter char on each side per the grammar -- a char-class trim would
+            // also eat a delimiter that happens to be part of the decoration name itself (e.g. the
+            // literal "+" decoration spelled "+!+" inside a "!"-delimited run)
+            let inner = &text[1..text.len() - 1];
+            let mut s = String::new();
+            s.push(*decoration_delim);
+            s.push_str(inner);
+            s.push(*decoration_delim);
+            (RString::Str(s), new_i)
+        }
         _ => {  // default rule, recursively gather children, if any
-            // _gather_children(i, q, qlen, input)
-            let x = _gather_children(i, q, qlen, input);
+            // _gather_children(i, q, qlen, input, decoration_delim)
+            let x = _gather_children(i, q, qlen, input, decoration_delim);
             x
         }
     }
 }
 
 pub fn canonify_abc_visitor(parser: &Rdp<pest::StringInput>) -> String {
-    visit_parse_tree(parser)
+    let mut decoration_delim = '!';
+    visit_parse_tree(parser, &mut decoration_delim)
+}
+
+// Like `canonify_abc_visitor`, but threads the '!'-vs-'+' long_gracing delimiter selected by an
+// 'I:decoration' field across calls, for callers canonifying a tune line by line.
+pub fn canonify_abc_visitor_with_decoration(parser: &Rdp<pest::StringInput>, decoration_delim: &mut char)
+                                             -> String {
+    visit_parse_tree(parser, decoration_delim)
+}
+
+// ======== Diatonic Transposition Visitor ========
+
+// Rewrites every `pitch` in the parse tree by a fixed musical interval, the way LilyPond's
+// `\transpose` does, representing each note as a (diatonic step 0-6 from C, chromatic alteration
+// in semitones, octave) triple and the interval as (diatonic steps, semitones). Also rewrites the
+// `K:` header (via the `key` rule, whether reached directly or through an inline `[K:...]`
+// field) by the same interval. Everything else -- note lengths, ties, slurs, decorations -- is
+// passed through untouched.
+//
+// `key_sig` is the signature of the transposed key in effect when this call begins (the source
+// key's tonic moved by the same interval, as produced by `pitch::key_signature`); notes without
+// an accidental of their own simply follow it. Since a line may contain an inline key change, the
+// (possibly updated) signature is handed back so the caller can carry it into the next line.
+//
+// `(diatonic_steps, semitones)` must name a real interval (e.g. a fifth up is (4, 7), not (4, 6));
+// passing a pair where the semitone count doesn't match any spelling reachable from the diatonic
+// step count is a caller bug -- see the note on `pitch::alteration_to_accidental`'s fallback.
+
+pub fn transpose_abc_visitor(parser: &Rdp<pest::StringInput>, diatonic_steps: i32, semitones: i32,
+                              key_sig: pitch::KeySignature) -> (String, pitch::KeySignature) {
+    let q = parser.queue();
+    let qlen = q.len();
+    let ilen = parser.input().len();
+    let input = parser.input().slice(0, ilen);
+    let mut sig = key_sig;
+    let mut result = String::new();
+    let mut i = 0;
+    while i < qlen {
+        let (rstring, new_i) = _recurse_transpose(i, q, qlen, input, diatonic_steps, semitones, &mut sig);
+        i = new_i;
+        result.push_str(&rstring.to_string(input));
+    }
+    (result, sig)
+}
+
+fn _gather_transpose(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str,
+                      dsteps: i32, semitones: i32, sig: &mut pitch::KeySignature) -> (RString, usize) {
+    let mut child_i = i + 1;
+    if child_i < qlen && q[child_i].start < q[i].end {
+        let mut text_offset = q[i].start;
+        let mut rstr;
+        if text_offset < q[child_i].start {
+            rstr = RString::from_slice(text_offset, q[child_i].start);
+            let (rstr2, new_i) = _recurse_transpose(child_i, q, qlen, input, dsteps, semitones, sig);
+            rstr = rstr.add(rstr2, input);
+            text_offset = q[child_i].end;
+            child_i = new_i;
+        } else {
+            let (rstr2, new_i) = _recurse_transpose(child_i, q, qlen, input, dsteps, semitones, sig);
+            rstr = rstr2;
+            text_offset = q[child_i].end;
+            child_i = new_i;
+        }
+        while child_i < qlen && q[child_i].start < q[i].end {
+            if text_offset < q[child_i].start {
+                rstr = rstr.add(RString::from_slice(text_offset, q[child_i].start), input);
+            }
+            let (rstr2, new_i) = _recurse_transpose(child_i, q, qlen, input, dsteps, semitones, sig);
+            rstr = rstr.add(rstr2, input);
+            text_offset = q[child_i].end;
+            child_i = new_i;
+        }
+        if text_offset < q[i].end {
+            rstr = rstr.add(RString::from_slice(text_offset, q[i].end), input);
+        }
+        (rstr, child_i)
+    } else {
+        (RString::from_slice(q[i].start, q[i].end), i + 1)
+    }
+}
+
+fn _recurse_transpose(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str,
+                       dsteps: i32, semitones: i32, sig: &mut pitch::KeySignature) -> (RString, usize) {
+    match q[i].rule {
+        Rule::pitch => transpose_pitch(i, q, qlen, input, dsteps, semitones, sig),
+        Rule::key => {
+            let (rstr, new_i, new_sig) = transpose_key(i, q, qlen, input, dsteps, semitones);
+            *sig = new_sig;
+            (rstr, new_i)
+        }
+        _ => _gather_transpose(i, q, qlen, input, dsteps, semitones, sig),
+    }
+}
+
+// Transpose a single `pitch = { accidental? ~ basenote ~ octave? }` node.
+fn transpose_pitch(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str,
+                    dsteps: i32, semitones: i32, sig: &pitch::KeySignature) -> (RString, usize) {
+    let end = q[i].end;
+    let mut child_i = i + 1;
+    let mut accidental: Option<&str> = None;
+    let mut basenote = 'C';
+    let mut octave_marks = 0i32;
+    while child_i < qlen && q[child_i].start < end {
+        match q[child_i].rule {
+            Rule::accidental => accidental = Some(&input[q[child_i].start..q[child_i].end]),
+            Rule::basenote => basenote = input[q[child_i].start..q[child_i].end].chars().next().unwrap(),
+            Rule::octave => octave_marks = pitch::octave_marks(&input[q[child_i].start..q[child_i].end]),
+            _ => {}
+        }
+        child_i += 1;
+    }
+
+    let step = pitch::step_of(basenote);
+    let raw_new_step = step as i32 + dsteps;
+    let new_step = raw_new_step.rem_euclid(7) as usize;
+    let octave_adjust = raw_new_step.div_euclid(7);
+
+    // `sig` is the transposed key's signature, so a note with no accidental of its own keeps
+    // following the key; an explicit accidental is moved by the interval, counting the 12
+    // semitones of any octave the new step wraps into
+    let alteration = match accidental.map(pitch::accidental_alteration) {
+        None => sig[new_step],
+        Some(explicit) => pitch::NATURAL_SEMITONE[step] + explicit + semitones
+                          - pitch::NATURAL_SEMITONE[new_step] - 12 * octave_adjust,
+    };
+
+    let target_octave = pitch::base_octave(basenote) + octave_marks + octave_adjust;
+    let new_letter = if target_octave <= 4 {
+        pitch::letter_of(new_step).to_ascii_uppercase()
+    } else {
+        pitch::letter_of(new_step).to_ascii_lowercase()
+    };
+    let new_marks = target_octave - pitch::base_octave(new_letter);
+
+    let mut s = String::new();
+    if alteration != sig[new_step] {
+        // a natural is spelled out only here, where the key alters this step
+        s.push_str(pitch::alteration_to_accidental(alteration).unwrap_or("="));
+    }
+    s.push(new_letter);
+    s.push_str(&pitch::octave_text(new_marks));
+    (RString::Str(s), child_i)
+}
+
+// Transpose the tonic of a `key = { ( key_def ~ ( WSP+ ~ clef )? ) | clef | "HP" | "Hp" }` node,
+// returning the updated key signature for use by subsequent pitches.
+fn transpose_key(i: usize, q: &Vec<Token<Rule>>, qlen: usize, input: &str,
+                  dsteps: i32, semitones: i32) -> (RString, usize, pitch::KeySignature) {
+    let key_end = q[i].end;
+    let mut child_i = i + 1;
+    let mut key_def_i = None;
+    while child_i < qlen && q[child_i].start < key_end {
+        if q[child_i].rule == Rule::key_def {
+            key_def_i = Some(child_i);
+        }
+        child_i += 1;
+    }
+    let new_i = child_i;
+    let key_def_i = match key_def_i {
+        Some(idx) => idx,
+        None => {
+            // a bare clef, or "HP"/"Hp": no tonic to transpose, and no signature to derive
+            return (RString::from_slice(q[i].start, key_end), new_i, [0; 7]);
+        }
+    };
+    let def_end = q[key_def_i].end;
+    let basenote_i = key_def_i + 1;
+    let basenote = input[q[basenote_i].start..q[basenote_i].end].chars().next().unwrap();
+    let after_basenote = q[basenote_i].end;
+
+    let mut next_i = basenote_i + 1;
+    let next_start = if next_i < qlen && q[next_i].start < def_end { q[next_i].start } else { def_end };
+    let tonic_accidental = match &input[after_basenote..next_start] {
+        "#" | "♯" => 1,
+        "b" | "♭" => -1,
+        _ => 0,
+    };
+
+    let mut mode = "maj";
+    // (span of the global_accidental node, its replacement text, and the transposed (accidental,
+    // basenote) pair to fold into the returned signature)
+    let mut global_edits: Vec<(usize, usize, String)> = Vec::new();
+    let mut global_pairs: Vec<(String, char)> = Vec::new();
+    while next_i < qlen && q[next_i].start < def_end {
+        match q[next_i].rule {
+            Rule::major => mode = "maj", Rule::lydian => mode = "lyd", Rule::ionian => mode = "ion",
+            Rule::mixolydian => mode = "mix", Rule::dorian => mode = "dor", Rule::aeolian => mode = "aeo",
+            Rule::phrygian => mode = "phr", Rule::locrian => mode = "loc", Rule::minor => mode = "min",
+            Rule::global_accidental => {
+                let ga_start = q[next_i].start;
+                let ga_end = q[next_i].end;
+                let acc_i = next_i + 1;
+                let base_i = acc_i + 1;
+                let g_accidental = &input[q[acc_i].start..q[acc_i].end];
+                let g_basenote = input[q[base_i].start..q[base_i].end].chars().next().unwrap();
+
+                let g_step = pitch::step_of(g_basenote);
+                let g_original_semitone = pitch::NATURAL_SEMITONE[g_step] + pitch::accidental_alteration(g_accidental);
+                let g_raw_step = g_step as i32 + dsteps;
+                let g_new_step = g_raw_step.rem_euclid(7) as usize;
+                let g_alteration = (g_original_semitone + semitones - pitch::NATURAL_SEMITONE[g_new_step]
+                                    - 12 * g_raw_step.div_euclid(7)).max(-2).min(2);
+                let g_new_token = match g_alteration {
+                    2 => "^^", 1 => "^", -1 => "_", -2 => "__", _ => "=",
+                };
+                let mut g_new_letter = pitch::letter_of(g_new_step);
+                if g_basenote.is_ascii_lowercase() {
+                    g_new_letter = g_new_letter.to_ascii_lowercase();
+                }
+
+                let mut rep = String::from(g_new_token);
+                rep.push(g_new_letter);
+                global_edits.push((ga_start, ga_end, rep));
+                global_pairs.push((g_new_token.to_string(), g_new_letter));
+            }
+            _ => {}
+        }
+        next_i += 1;
+    }
+
+    let step = pitch::step_of(basenote);
+    let original_semitone = pitch::NATURAL_SEMITONE[step] + tonic_accidental;
+    let raw_new_step = step as i32 + dsteps;
+    let new_step = raw_new_step.rem_euclid(7) as usize;
+    let desired = original_semitone + semitones;
+    // a key's tonic is only ever spelled natural, sharp, or flat
+    let alteration = (desired - pitch::NATURAL_SEMITONE[new_step] - 12 * raw_new_step.div_euclid(7)).max(-1).min(1);
+    let new_letter = pitch::letter_of(new_step);
+    let new_accidental = match alteration { 1 => "#", -1 => "b", _ => "" };
+
+    let mut new_sig = pitch::key_signature(new_letter, if alteration == 0 { None } else { Some(new_accidental) },
+                                            mode);
+    pitch::apply_global_accidentals(&mut new_sig, &global_pairs);
+
+    let mut rstr = RString::from_str(&new_letter.to_string());
+    rstr = rstr.add(RString::from_str(new_accidental), input);
+    // the mode and a trailing clef are passed through unchanged; each global_accidental clause's
+    // own pitch is transposed the same way the tonic is
+    let mut pos = next_start;
+    for (g_start, g_end, rep) in &global_edits {
+        if pos < *g_start {
+            rstr = rstr.add(RString::from_slice(pos, *g_start), input);
+        }
+        rstr = rstr.add(RString::from_str(rep), input);
+        pos = *g_end;
+    }
+    if pos < key_end {
+        rstr = rstr.add(RString::from_slice(pos, key_end), input);
+    }
+    (rstr, new_i, new_sig)
 }
 
@@ -0,0 +1,222 @@
+// ABCdb abcparser_peg.rs – voice.rs
+//
+// Copyright © 2017 Sean Bolton.
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Reconstructs the overlaid voices in a polyphonic tune body into separate logical streams, the
+// way LilyPond puts multiple voices on one staff: every `rollback` ('&') starts a new overlay
+// layer of whichever voice (`ifield_voice`/`voice`) is currently selected, with its running time
+// reset to the start of the current measure. Consumers (canonify, transpose, the LilyPond
+// backend) can then walk each voice's stream independently instead of treating the overlay as
+// opaque inline text.
+
+extern crate pest;
+
+use std::collections::HashMap;
+
+use pest::prelude::*;
+
+use grammar::{Rdp,Rule};
+use length;
+use tuplet::{self,TupletGroup};
+
+// identifies one overlay layer of one voice: layer 0 is the voice's primary line, layer 1 is
+// what follows the first '&' since the voice was last selected or the last barline, and so on
+pub type VoiceKey = (String, u32);
+
+#[derive(Debug, Clone)]
+pub struct VoiceElement {
+    pub queue_index: usize,
+    pub onset: (u32, u32), // time since the tune began, in whole notes
+}
+
+pub struct VoiceState {
+    label: String,
+    layer: u32,
+    measure_start: (u32, u32),
+    time_by_key: HashMap<VoiceKey, (u32, u32)>,
+    // each key's previous stem's notated length, held back in case a following '<'/'>' adjusts
+    // it -- mirrors MeasureState's carry in duration.rs, but one per key since every voice/layer
+    // runs independently and a key can go idle (on '&', on a voice switch) with its carry still
+    // outstanding
+    carry: HashMap<VoiceKey, (u32, u32)>,
+    pending_broken_sep: Option<char>,
+    pub streams: HashMap<VoiceKey, Vec<VoiceElement>>,
+}
+
+impl VoiceState {
+    pub fn new() -> VoiceState {
+        VoiceState {
+            label: "1".to_string(),
+            layer: 0,
+            measure_start: (0, 1),
+            time_by_key: HashMap::new(),
+            carry: HashMap::new(),
+            pending_broken_sep: None,
+            streams: HashMap::new(),
+        }
+    }
+}
+
+fn is_recordable(rule: Rule) -> bool {
+    match rule {
+        Rule::stem | Rule::chord_or_text | Rule::gracing | Rule::grace_notes | Rule::tuplet |
+        Rule::slur_begin | Rule::slur_end | Rule::multi_measure_rest | Rule::measure_repeat |
+        Rule::nth_repeat | Rule::end_nth_repeat | Rule::hard_line_break => true,
+        _ => false,
+    }
+}
+
+fn children_end(i: usize, q: &Vec<Token<Rule>>, qlen: usize) -> usize {
+    let end = q[i].end;
+    let mut k = i + 1;
+    while k < qlen && q[k].start < end {
+        k += 1;
+    }
+    k
+}
+
+fn mul(a: (u32, u32), b: (u32, u32)) -> (u32, u32) {
+    length::reduce(a.0 * b.0, a.1 * b.1)
+}
+
+// commit a note's notated length into the running time of its voice/layer
+fn add_to_time(state: &mut VoiceState, key: &VoiceKey, note: (u32, u32)) {
+    let t = state.time_by_key.entry(key.clone()).or_insert(state.measure_start);
+    *t = length::reduce(t.0 * note.1 + note.0 * t.1, t.1 * note.1);
+}
+
+// flush any pending carry belonging to `key` into its running time, unadjusted
+fn flush_carry(state: &mut VoiceState, key: &VoiceKey) {
+    if let Some(note) = state.carry.remove(key) {
+        add_to_time(state, key, note);
+    }
+}
+
+// flush every key's outstanding carry, unadjusted -- used at a barline, since any key that went
+// idle mid-measure (e.g. the layer before an '&') must still have its last note's length folded
+// in before that key's running time is read for the next measure
+fn flush_all_carries(state: &mut VoiceState) {
+    let pending: Vec<VoiceKey> = state.carry.keys().cloned().collect();
+    for key in pending {
+        flush_carry(state, &key);
+    }
+}
+
+// Walk one already-parsed tune body line, updating `state` (which is threaded across lines so a
+// voice's running time, and the measure it's in, survive a line break).
+pub fn separate_voices(parser: &Rdp<pest::StringInput>, unit_length: (u32, u32),
+                        tuplet_groups: &[TupletGroup], state: &mut VoiceState) {
+    let q = parser.queue();
+    let qlen = q.len();
+    let input = parser.input().slice(0, parser.input().len());
+
+    let tuplet_ratio = tuplet::ratio_by_member(tuplet_groups);
+
+    let mut i = 0;
+    while i < qlen {
+        match q[i].rule {
+            Rule::ifield_voice => {
+                // the voice being switched away from goes idle; its last stem's carry won't be
+                // seen again until this voice is selected again, so commit it now
+                flush_carry(state, &(state.label.clone(), state.layer));
+                let new_i = children_end(i, q, qlen);
+                let mut k = i + 1;
+                while k < new_i {
+                    if q[k].rule == Rule::voice {
+                        let text = &input[q[k].start..q[k].end];
+                        state.label = text.split_whitespace().next().unwrap_or("1").to_string();
+                        state.layer = 0;
+                    }
+                    k += 1;
+                }
+                i = new_i;
+                continue;
+            }
+            Rule::rollback => {
+                // the layer being rolled back from goes idle the same way a voice switch does
+                flush_carry(state, &(state.label.clone(), state.layer));
+                state.layer += 1;
+                let key = (state.label.clone(), state.layer);
+                state.time_by_key.insert(key, state.measure_start);
+            }
+            Rule::b_sep => {
+                state.pending_broken_sep = Some(input.as_bytes()[q[i].start] as char);
+            }
+            Rule::barline => {
+                // all layers of the current voice are expected to reconverge at the barline;
+                // every key's carry must be folded in first (not just the primary's -- a layer
+                // left idle by an '&' earlier in the measure may still have one outstanding),
+                // since layer 0's running time is the reference point for the next measure
+                flush_all_carries(state);
+                let primary = (state.label.clone(), 0);
+                state.measure_start = *state.time_by_key.get(&primary).unwrap_or(&state.measure_start);
+                state.layer = 0;
+            }
+            rule if is_recordable(rule) => {
+                let key = (state.label.clone(), state.layer);
+                // the adjusted length committed for the *previous* stem's carry, when this stem
+                // follows a broken-rhythm separator that pairs with it
+                let mut adjusted_prev = None;
+                if rule == Rule::stem {
+                    let mut cur = length::stem_length_factor(i, q, qlen, input);
+                    if let Some((num, den)) = tuplet_ratio.get(&i) {
+                        cur = length::reduce(cur.0 * num, cur.1 * den);
+                    }
+                    cur = length::reduce(unit_length.0 * cur.0, unit_length.1 * cur.1);
+
+                    let sep = state.pending_broken_sep.take();
+                    match (sep, state.carry.remove(&key)) {
+                        (Some(sep), Some(prev)) => {
+                            let (prev_adj, cur_adj) = if sep == '>' {
+                                (mul(prev, (3, 2)), mul(cur, (1, 2)))
+                            } else {
+                                (mul(prev, (1, 2)), mul(cur, (3, 2)))
+                            };
+                            adjusted_prev = Some(prev_adj);
+                            state.carry.insert(key.clone(), cur_adj);
+                        }
+                        (_, Some(prev)) => {
+                            add_to_time(state, &key, prev);
+                            state.carry.insert(key.clone(), cur);
+                        }
+                        (_, None) => {
+                            state.carry.insert(key.clone(), cur);
+                        }
+                    }
+                } else {
+                    flush_carry(state, &key);
+                }
+                if let Some(prev_adj) = adjusted_prev {
+                    add_to_time(state, &key, prev_adj);
+                }
+
+                let onset = *state.time_by_key.entry(key.clone()).or_insert(state.measure_start);
+                state.streams.entry(key.clone()).or_insert_with(Vec::new)
+                    .push(VoiceElement { queue_index: i, onset });
+                i = children_end(i, q, qlen);
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+}